@@ -0,0 +1,135 @@
+//! A standalone, long-polling SQS consumer for deployments where per-event Lambda invocation is
+//! cost-prohibitive. This is a separate binary target from the Lambda entry point in `main.rs`
+//! precisely because it pulls in `aws-sdk-sqs`, which that entry point does not need; a manifest
+//! for this crate does not exist yet in this tree, so there is no `standalone` Cargo feature or
+//! `required-features` entry gating it today. Once a `Cargo.toml` is added, this binary and its
+//! `aws-sdk-sqs` dependency should be placed behind a `standalone` feature so consumers of the
+//! Lambda entry point alone don't build or pull in either.
+//!
+//! It shares the same restructuring core ([lambda_s3_restructure::restructure_event]) as the
+//! Lambda entry point, so a rule config file written for one works unchanged for the other. A
+//! message is only deleted from the queue once every S3 entity it describes has been copied
+//! successfully; messages that fail are left in place so SQS redrives them.
+
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_sqs::types::DeleteMessageBatchRequestEntry;
+use aws_sdk_sqs::Client as SqsClient;
+use tracing::log::*;
+
+use lambda_s3_restructure::config::Config;
+use lambda_s3_restructure::{compile_rules, restructure_event, s3_records_from_message_body};
+
+/// Settings controlling how the daemon polls its SQS queue, read from the environment so a
+/// deployment looks the same whether it is running the Lambda entry point or this daemon.
+struct SqsSettings {
+    queue_url: String,
+    max_messages: i32,
+    wait_time_seconds: i32,
+    visibility_timeout_seconds: i32,
+}
+
+impl SqsSettings {
+    fn from_env() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            queue_url: std::env::var("SQS_QUEUE_URL")
+                .map_err(|_| anyhow::anyhow!("You must define SQS_QUEUE_URL in the environment"))?,
+            max_messages: env_var_or("SQS_MAX_MESSAGES", 10),
+            wait_time_seconds: env_var_or("SQS_WAIT_TIME_SECONDS", 20),
+            visibility_timeout_seconds: env_var_or("SQS_VISIBILITY_TIMEOUT_SECONDS", 30),
+        })
+    }
+}
+
+fn env_var_or(key: &str, default: i32) -> i32 {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_target(false)
+        .without_time()
+        .init();
+
+    let settings = SqsSettings::from_env()?;
+    let config = Config::load_from_env()?;
+    let (router, rules) = compile_rules(&config)?;
+
+    let shared_config = aws_config::from_env().load().await;
+    let s3_client = S3Client::new(&shared_config);
+    let sqs_client = SqsClient::new(&shared_config);
+
+    info!(
+        "Starting standalone long-poll consumer for {}",
+        settings.queue_url
+    );
+
+    loop {
+        let received = sqs_client
+            .receive_message()
+            .queue_url(&settings.queue_url)
+            .max_number_of_messages(settings.max_messages)
+            .wait_time_seconds(settings.wait_time_seconds)
+            .visibility_timeout(settings.visibility_timeout_seconds)
+            .send()
+            .await?;
+
+        let messages = received.messages.unwrap_or_default();
+        if messages.is_empty() {
+            continue;
+        }
+
+        let mut to_delete = vec![];
+
+        for message in messages {
+            let (Some(body), Some(receipt_handle), Some(message_id)) =
+                (&message.body, &message.receipt_handle, &message.message_id)
+            else {
+                warn!("Received a message missing a body, receipt handle, or id, skipping");
+                continue;
+            };
+
+            match s3_records_from_message_body(body) {
+                Ok(records) => {
+                    let event = aws_lambda_events::s3::S3Event { records };
+                    match restructure_event(
+                        &s3_client,
+                        &router,
+                        &rules,
+                        &config.event_names,
+                        config.concurrency,
+                        event,
+                    )
+                    .await
+                    {
+                        Ok(()) => to_delete.push(
+                            DeleteMessageBatchRequestEntry::builder()
+                                .id(message_id.clone())
+                                .receipt_handle(receipt_handle.clone())
+                                .build()?,
+                        ),
+                        Err(err) => {
+                            error!("Failed to restructure message {message_id}, leaving for redrive: {err:?}")
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Failed to parse message {message_id}, leaving for redrive: {err:?}")
+                }
+            }
+        }
+
+        if !to_delete.is_empty() {
+            sqs_client
+                .delete_message_batch()
+                .queue_url(&settings.queue_url)
+                .set_entries(Some(to_delete))
+                .send()
+                .await?;
+        }
+    }
+}
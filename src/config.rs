@@ -0,0 +1,163 @@
+//! Configuration structures for describing one or more restructuring rules.
+//!
+//! Historically this Lambda read a single `INPUT_PATTERN`/`EXCLUDE_REGEX`/`OUTPUT_TEMPLATE` triple
+//! from the environment. That only supports restructuring a single prefix/table layout per
+//! deployment. [Config] allows a single deployment to describe many rules, mirroring the
+//! `sources:` list style used by delta-s3-loader's `config.yml`.
+
+use serde::Deserialize;
+
+/// A single restructuring rule: the pattern used to recognize matching object keys, an optional
+/// regex for keys which should be ignored even when matched, the Liquid template used to render
+/// the destination key, and an optional bucket to copy into (defaulting to the source bucket).
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    pub input_pattern: String,
+    pub exclude_regex: Option<String>,
+    pub output_template: String,
+    pub output_bucket: Option<String>,
+}
+
+/// The event names processed by default, when neither a `CONFIG_FILE` nor the legacy
+/// `EVENT_NAME_ALLOWLIST` environment variable specifies one. `ObjectCreated:*` covers puts,
+/// posts, copies, and completed multipart uploads, while excluding `ObjectRemoved:*`, lifecycle
+/// transitions, and multipart-abort events which should never trigger a restructure.
+fn default_event_names() -> Vec<String> {
+    vec!["ObjectCreated:*".to_string()]
+}
+
+/// The number of `copy_object` requests allowed in flight at once, when neither a `CONFIG_FILE`
+/// nor the legacy `COPY_CONCURRENCY` environment variable specifies one.
+fn default_concurrency() -> usize {
+    10
+}
+
+/// The full set of rules loaded from a `CONFIG_FILE`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub rules: Vec<Rule>,
+    /// Allow-list of `eventName` patterns (a trailing `*` matches as a prefix) which should be
+    /// acted on; everything else is ignored. Defaults to `["ObjectCreated:*"]`.
+    #[serde(default = "default_event_names")]
+    pub event_names: Vec<String>,
+    /// How many `copy_object` requests may be in flight concurrently. Defaults to `10`.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+}
+
+impl Config {
+    /// Load and parse a [Config] from the YAML file at `path`
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let raw = std::fs::read_to_string(path)?;
+        Ok(serde_yaml::from_str(&raw)?)
+    }
+
+    /// Build a single-rule [Config] from the legacy `INPUT_PATTERN`/`EXCLUDE_REGEX`/
+    /// `OUTPUT_TEMPLATE`/`OUTPUT_BUCKET`/`EVENT_NAME_ALLOWLIST`/`COPY_CONCURRENCY` environment
+    /// variables, for deployments which have not migrated to a `CONFIG_FILE` yet.
+    pub fn from_legacy_env() -> Result<Self, anyhow::Error> {
+        let input_pattern = std::env::var("INPUT_PATTERN").map_err(|_| {
+            anyhow::anyhow!("You must define CONFIG_FILE or INPUT_PATTERN in the environment")
+        })?;
+        let output_template = std::env::var("OUTPUT_TEMPLATE")
+            .map_err(|_| anyhow::anyhow!("You must define OUTPUT_TEMPLATE in the environment"))?;
+        let event_names = std::env::var("EVENT_NAME_ALLOWLIST")
+            .ok()
+            .map(|allowlist| {
+                allowlist
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .collect()
+            })
+            .unwrap_or_else(default_event_names);
+        let concurrency = std::env::var("COPY_CONCURRENCY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(default_concurrency);
+
+        Ok(Config {
+            rules: vec![Rule {
+                input_pattern,
+                exclude_regex: std::env::var("EXCLUDE_REGEX").ok(),
+                output_template,
+                output_bucket: std::env::var("OUTPUT_BUCKET").ok(),
+            }],
+            event_names,
+            concurrency,
+        })
+    }
+
+    /// Load the [Config] from the file referenced by `CONFIG_FILE`, falling back to the legacy
+    /// single-rule environment variables when `CONFIG_FILE` is not set
+    pub fn load_from_env() -> Result<Self, anyhow::Error> {
+        match std::env::var("CONFIG_FILE") {
+            Ok(path) => Self::load(&path),
+            Err(_) => Self::from_legacy_env(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multi_rule_config() {
+        let raw = r#"
+rules:
+  - input_pattern: "databases/alpha/:table/:filename"
+    output_template: "restructured/alpha/{{table}}/{{filename}}"
+  - input_pattern: "databases/bravo/:table/:filename"
+    exclude_regex: "^databases/bravo/_tmp/.*"
+    output_template: "restructured/bravo/{{table}}/{{filename}}"
+    output_bucket: "bravo-output-bucket"
+"#;
+        let config: Config = serde_yaml::from_str(raw).expect("Failed to parse config");
+        assert_eq!(config.rules.len(), 2);
+        assert_eq!(config.rules[0].input_pattern, "databases/alpha/:table/:filename");
+        assert!(config.rules[0].exclude_regex.is_none());
+        assert_eq!(config.rules[1].output_bucket.as_deref(), Some("bravo-output-bucket"));
+        assert_eq!(config.event_names, vec!["ObjectCreated:*".to_string()]);
+        assert_eq!(config.concurrency, 10);
+    }
+
+    #[test]
+    fn test_parse_config_with_concurrency() {
+        let raw = r#"
+concurrency: 25
+rules:
+  - input_pattern: "databases/alpha/:table/:filename"
+    output_template: "restructured/alpha/{{table}}/{{filename}}"
+"#;
+        let config: Config = serde_yaml::from_str(raw).expect("Failed to parse config");
+        assert_eq!(config.concurrency, 25);
+    }
+
+    #[test]
+    fn test_parse_config_with_event_names() {
+        let raw = r#"
+event_names:
+  - "ObjectCreated:Put"
+  - "ObjectCreated:CompleteMultipartUpload"
+rules:
+  - input_pattern: "databases/alpha/:table/:filename"
+    output_template: "restructured/alpha/{{table}}/{{filename}}"
+"#;
+        let config: Config = serde_yaml::from_str(raw).expect("Failed to parse config");
+        assert_eq!(
+            config.event_names,
+            vec![
+                "ObjectCreated:Put".to_string(),
+                "ObjectCreated:CompleteMultipartUpload".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_legacy_env_missing_input_pattern() {
+        std::env::remove_var("INPUT_PATTERN");
+        std::env::remove_var("CONFIG_FILE");
+        let result = Config::from_legacy_env();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,622 @@
+use aws_lambda_events::event::s3::{S3Entity, S3Event};
+use aws_lambda_events::sqs::SqsEvent;
+use aws_sdk_s3::Client as S3Client;
+use futures::stream::{self, StreamExt};
+use lambda_runtime::Error;
+use regex::Regex;
+use routefinder::Router;
+use tracing::log::*;
+
+use std::collections::HashMap;
+
+pub mod config;
+
+use config::Config;
+
+/// A [config::Rule] with its `output_template` compiled to a [liquid::Template] and its
+/// `exclude_regex` compiled to a [Regex], ready to be applied to matching object keys.
+pub struct CompiledRule {
+    template: liquid::Template,
+    exclude_regex: Option<Regex>,
+    output_bucket: Option<String>,
+}
+
+/// Build a [Router] keyed by rule index alongside the compiled form of each [config::Rule], so a
+/// single deployment can restructure many prefixes/tables with different layouts.
+pub fn compile_rules(config: &Config) -> Result<(Router<usize>, Vec<CompiledRule>), Error> {
+    let parser = liquid::ParserBuilder::with_stdlib().build()?;
+    let mut router = Router::new();
+    let mut compiled = Vec::with_capacity(config.rules.len());
+
+    for (id, rule) in config.rules.iter().enumerate() {
+        router.add(rule.input_pattern.clone(), id)?;
+        let exclude_regex = rule
+            .exclude_regex
+            .as_ref()
+            .map(|ex| Regex::new(ex))
+            .transpose()?;
+        compiled.push(CompiledRule {
+            template: parser.parse(&rule.output_template)?,
+            exclude_regex,
+            output_bucket: rule.output_bucket.clone(),
+        });
+    }
+
+    Ok((router, compiled))
+}
+
+/// The shape Lambda expects back from a function configured with `ReportBatchItemFailures` for
+/// an SQS event source: only the messages named here are redriven, everything else in the batch
+/// is considered successfully processed and deleted from the queue.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct BatchResponse {
+    #[serde(rename = "batchItemFailures")]
+    pub batch_item_failures: Vec<BatchItemFailure>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BatchItemFailure {
+    #[serde(rename = "itemIdentifier")]
+    pub item_identifier: String,
+}
+
+impl From<Vec<String>> for BatchResponse {
+    fn from(failed_message_ids: Vec<String>) -> Self {
+        Self {
+            batch_item_failures: failed_message_ids
+                .into_iter()
+                .map(|item_identifier| BatchItemFailure { item_identifier })
+                .collect(),
+        }
+    }
+}
+
+/// A simple structure to make deserializing test events for identification easier
+///
+/// See <fhttps://github.com/buoyant-data/oxbow/issues/8>
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TestEvent {
+    event: String,
+}
+
+/// An [aws_lambda_events::s3::S3EventRecord] tagged with the id of the SQS message it was
+/// delivered in, if any, so that a failure to restructure it can be reported back as a partial
+/// batch failure against that specific message rather than the whole batch.
+pub struct RecordWithOrigin {
+    pub record: aws_lambda_events::s3::S3EventRecord,
+    pub message_id: Option<String>,
+}
+
+/// Convert the given [aws_lambda_events::sqs::SqsEvent] to a collection of
+///  [RecordWithOrigin], tagging each with the SQS `message_id` it came from. This is mostly
+///  useful for handling S3 Bucket Notifications which have been passed into SQS
+///
+///  In the case where the [aws_lambda_events::sqs::SqsEvent] contains an `s3:TestEvent` which is
+///  fired when S3 Bucket Notifications are first enabled, the event will be ignored to avoid
+///  errorsin the processing pipeline
+pub fn s3_from_sqs(event: SqsEvent) -> Result<Vec<RecordWithOrigin>, anyhow::Error> {
+    let mut records = vec![];
+    for message in event.records.iter() {
+        /* each record is an SqsMessage */
+        if let Some(body) = &message.body {
+            for record in s3_records_from_message_body(body)? {
+                records.push(RecordWithOrigin {
+                    record,
+                    message_id: message.message_id.clone(),
+                });
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Parse a single raw SQS message body into the [aws_lambda_events::s3::S3EventRecord]s it
+/// contains, tolerating the `s3:TestEvent` fired when bucket notifications are first configured.
+/// Factored out of [s3_from_sqs] so the standalone SQS consumer, which receives message bodies
+/// one at a time rather than as a batch, can reuse the same parsing/ignore rules.
+pub fn s3_records_from_message_body(
+    body: &str,
+) -> Result<Vec<aws_lambda_events::s3::S3EventRecord>, anyhow::Error> {
+    match serde_json::from_str::<S3Event>(body) {
+        Ok(s3event) => Ok(s3event.records),
+        Err(err) => {
+            // if we cannot deserialize and the event is an s3::TestEvent, then we should
+            // just return empty records.
+            let test_event = serde_json::from_str::<TestEvent>(body);
+            // Early exit with the original error if we cannot parse the JSON at all
+            if test_event.is_err() {
+                return Err(err.into());
+            }
+
+            // Ignore the error on deserialization if the event ends up being an S3
+            // TestEvent which is fired when bucket notifications are originally configured
+            if "s3:TestEvent" != test_event.unwrap().event {
+                return Err(err.into());
+            }
+
+            Ok(vec![])
+        }
+    }
+}
+
+/// Restructure a single [S3Entity]: match it against `router`/`rules`, and if it matches and is
+/// not excluded, copy it to its rendered destination key. This is the core unit of work shared by
+/// the Lambda entry point and the standalone long-polling daemon.
+pub async fn restructure_entity(
+    client: &S3Client,
+    router: &Router<usize>,
+    rules: &[CompiledRule],
+    entity: S3Entity,
+) -> Result<(), Error> {
+    debug!("Processing {entity:?}");
+
+    if let Some(encoded_key) = entity.object.key {
+        // S3 bucket notifications deliver keys percent-encoded, so decode before matching
+        // and rendering templates; `copy_source` needs its own re-encoding (see
+        // `encode_copy_source_key`) since it has different escaping rules than the notification.
+        let source_key = url_decode_key(&encoded_key)?;
+
+        let (rule_id, parameters) = match captured_parameters(router, &source_key) {
+            Some((rule_id, params)) => (rule_id, add_builtin_parameters(params)),
+            None => {
+                info!("Triggered with {source_key} which does not match any input pattern, ignoring");
+                return Ok(());
+            }
+        };
+        let rule = &rules[rule_id];
+
+        if should_exclude(rule.exclude_regex.as_ref(), &source_key) {
+            return Ok(());
+        }
+
+        let output_key = rule.template.render(&parameters)?;
+        info!("Copying {source_key:?} to {output_key:?}");
+        if let Some(bucket) = entity.bucket.name {
+            let output_bucket = rule.output_bucket.clone().unwrap_or(bucket.clone());
+            let copy_source_key = encode_copy_source_key(&source_key);
+            debug!("Sending a copy request for {output_bucket} with {bucket}/{copy_source_key} to {output_key}");
+            let result = client
+                .copy_object()
+                .bucket(&output_bucket)
+                .copy_source(format!("{bucket}/{copy_source_key}"))
+                .key(output_key)
+                .send()
+                .await?;
+            debug!("Copied object: {result:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Restructure every entity in an [S3Event], with up to `concurrency` copies in flight at once.
+/// This is the shape of work a single direct Lambda invocation or a single standalone-consumer
+/// message needs to perform.
+pub async fn restructure_event(
+    client: &S3Client,
+    router: &Router<usize>,
+    rules: &[CompiledRule],
+    event_names: &[String],
+    concurrency: usize,
+    event: S3Event,
+) -> Result<(), Error> {
+    let entities = entities_from(event, event_names)?;
+    let results: Vec<Result<(), Error>> = stream::iter(entities)
+        .map(|entity| restructure_entity(client, router, rules, entity))
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut first_error = None;
+    for result in results {
+        if let Err(err) = result {
+            error!("Failed to restructure an entity: {err:?}");
+            if first_error.is_none() {
+                first_error = Some(err);
+            }
+        }
+    }
+    if let Some(err) = first_error {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Restructure every entity in a collection of [RecordWithOrigin], with up to `concurrency`
+/// copies in flight at once, returning the `message_id` of each SQS message whose entity failed
+/// to restructure so the caller can report a partial batch failure instead of redriving messages
+/// which already succeeded. An entity with no `message_id` (i.e. the function was invoked
+/// directly with an [S3Event] rather than via SQS) makes the whole call return that error once
+/// all copies have finished, since there is no message to single out for redrive.
+pub async fn restructure_records(
+    client: &S3Client,
+    router: &Router<usize>,
+    rules: &[CompiledRule],
+    event_names: &[String],
+    concurrency: usize,
+    records: Vec<RecordWithOrigin>,
+) -> Result<Vec<String>, Error> {
+    let entities = entities_with_origin_from(records, event_names)?;
+
+    let results: Vec<(Option<String>, Result<(), Error>)> = stream::iter(entities)
+        .map(|(message_id, entity)| async move {
+            (message_id, restructure_entity(client, router, rules, entity).await)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut failed_message_ids = vec![];
+    for (message_id, result) in results {
+        if let Err(err) = result {
+            match message_id {
+                Some(id) => {
+                    error!("Failed to restructure an entity from message {id}, marking for redrive: {err:?}");
+                    failed_message_ids.push(id);
+                }
+                None => return Err(err),
+            }
+        }
+    }
+
+    Ok(failed_message_ids)
+}
+
+/// Return true if `event_name` matches one of the configured allow-list patterns. A pattern
+/// ending in `*` matches any event name sharing its prefix (e.g. `ObjectCreated:*` matches both
+/// `ObjectCreated:Put` and `ObjectCreated:CompleteMultipartUpload`); anything else must match
+/// exactly.
+pub fn event_name_allowed(allowlist: &[String], event_name: &str) -> bool {
+    allowlist.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => event_name.starts_with(prefix),
+        None => pattern == event_name,
+    })
+}
+
+/// Return the deserialized and useful objects from the event payload
+///
+/// This function will apply a filter to make sure that it is only return objects which have been
+/// put in this invocation and whose `eventName` is in the `event_names` allow-list, so deletes,
+/// lifecycle transitions, and multipart-abort events don't trigger spurious restructures
+pub fn entities_from(event: S3Event, event_names: &[String]) -> Result<Vec<S3Entity>, anyhow::Error> {
+    Ok(event
+        .records
+        .into_iter()
+        // only bother with the record if the key is present
+        .filter(|r| r.s3.object.key.is_some())
+        .filter(|r| event_name_allowed(event_names, r.event_name.as_deref().unwrap_or_default()))
+        .map(|r| r.s3)
+        .collect())
+}
+
+/// Return the deserialized and useful objects from a collection of [RecordWithOrigin], keeping
+/// each entity's originating `message_id` alongside it
+///
+/// This function will apply a filter to make sure that it is only return objects which have been
+/// put in this invocation and whose `eventName` is in the `event_names` allow-list, so deletes,
+/// lifecycle transitions, and multipart-abort events don't trigger spurious restructures
+pub fn entities_with_origin_from(
+    records: Vec<RecordWithOrigin>,
+    event_names: &[String],
+) -> Result<Vec<(Option<String>, S3Entity)>, anyhow::Error> {
+    Ok(records
+        .into_iter()
+        // only bother with the record if the key is present
+        .filter(|r| r.record.s3.object.key.is_some())
+        .filter(|r| {
+            event_name_allowed(
+                event_names,
+                r.record.event_name.as_deref().unwrap_or_default(),
+            )
+        })
+        .map(|r| (r.message_id, r.record.s3))
+        .collect())
+}
+
+/// Take the source key and the already configured router in order to access the id of whichever
+/// rule matched along with a collection of captured parameters in a HashMap format
+pub fn captured_parameters<Handler: Copy>(
+    router: &Router<Handler>,
+    source_key: &str,
+) -> Option<(Handler, HashMap<String, String>)> {
+    let matches = router.matches(source_key);
+
+    if matches.is_empty() {
+        return None;
+    }
+
+    let mut data: HashMap<String, String> = HashMap::new();
+    for capture in matches[0].captures().into_iter() {
+        data.insert(capture.name().into(), capture.value().into());
+    }
+    Some((*matches[0], data))
+}
+
+/// S3 bucket notifications percent-encode object keys, representing a literal space as `+`
+/// rather than `%20` (the `application/x-www-form-urlencoded` convention rather than plain URL
+/// path encoding). Decode a key back to the form it was actually stored under, so it can be
+/// matched against input patterns and rendered into output templates.
+pub fn url_decode_key(key: &str) -> Result<String, anyhow::Error> {
+    let decoded = urlencoding::decode(&key.replace('+', " "))?;
+    Ok(decoded.into_owned())
+}
+
+/// Percent-encode a decoded object key for use in the `x-amz-copy-source` header of a
+/// [aws_sdk_s3::Client::copy_object] request. This header is parsed by S3 as a plain URL path,
+/// where a space must be `%20`, unlike the `application/x-www-form-urlencoded` encoding S3
+/// bucket notifications use (see [url_decode_key]); each path segment is encoded independently
+/// so that literal `/` separators are preserved rather than escaped to `%2F`.
+pub fn encode_copy_source_key(key: &str) -> String {
+    key.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Return true if the given key matches the pattern and should be excluded from consideration
+pub fn should_exclude(pattern: Option<&Regex>, key: &str) -> bool {
+    match pattern {
+        Some(re) => re.is_match(key),
+        None => false,
+    }
+}
+
+/// Introduce the necessary built-in parameters to the `data` for rendering a Handlebars template
+pub fn add_builtin_parameters(mut data: HashMap<String, String>) -> HashMap<String, String> {
+    use chrono::Datelike;
+    let now = chrono::Utc::now();
+    data.insert("year".into(), format!("{}", now.year()));
+    data.insert("month".into(), format!("{}", now.month()));
+    data.insert("day".into(), format!("{}", now.day()));
+    data.insert("ds".into(), format!("{}", now.format("%Y-%m-%d")));
+    data.insert(
+        "region".into(),
+        std::env::var("AWS_REGION").unwrap_or("unknown".into()),
+    );
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_builtins() {
+        let data = add_builtin_parameters(HashMap::new());
+        assert!(data.contains_key("year"), "builtins needs `year`");
+        assert!(data.contains_key("month"), "builtins needs `month`");
+        assert!(data.contains_key("day"), "builtins needs `day`");
+        assert!(data.contains_key("ds"), "builtins needs `ds`");
+        assert!(data.contains_key("region"), "builtins needs `region`");
+    }
+
+    #[test]
+    fn test_input_router() -> Result<(), anyhow::Error> {
+        let input_pattern = "path/:ignore/:database/:table/1/:filename";
+        let source_key = "path/testing-2023-08-18-07-05-df7d7bcc-3160-50da-8c4c-26952b11a4c/testdb/public.test_table/1/foobar.snappy.parquet";
+
+        let mut router = Router::new();
+        let _ = router.add(input_pattern, 1);
+
+        assert_eq!(router.matches("test/key").len(), 0);
+        let matches = router.matches(source_key);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            matches[0].captures().get("filename"),
+            Some("foobar.snappy.parquet")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_entities_from_event() -> Result<(), anyhow::Error> {
+        let event = load_test_event()?;
+        let objects = entities_from(event, &["ObjectCreated:*".to_string()])?;
+        assert_eq!(objects.len(), 1);
+        assert!(objects[0].object.key.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_name_allowed() {
+        let allowlist = vec!["ObjectCreated:*".to_string()];
+        assert!(event_name_allowed(&allowlist, "ObjectCreated:Put"));
+        assert!(event_name_allowed(
+            &allowlist,
+            "ObjectCreated:CompleteMultipartUpload"
+        ));
+        assert!(!event_name_allowed(&allowlist, "ObjectRemoved:Delete"));
+    }
+
+    #[test]
+    fn test_entities_from_filters_disallowed_event_names() -> Result<(), anyhow::Error> {
+        let mut event = load_test_event()?;
+        event.records[0].event_name = Some("ObjectRemoved:Delete".to_string());
+        let objects = entities_from(event, &["ObjectCreated:*".to_string()])?;
+        assert_eq!(objects.len(), 0);
+
+        Ok(())
+    }
+
+    /**
+     * Return a simple test event from the Lambda built-in test tool
+     */
+    fn load_test_event() -> Result<S3Event, anyhow::Error> {
+        let raw_buf = r#"
+{
+  "Records": [
+    {
+      "eventVersion": "2.0",
+      "eventSource": "aws:s3",
+      "awsRegion": "us-east-1",
+      "eventTime": "1970-01-01T00:00:00.000Z",
+      "eventName": "ObjectCreated:Put",
+      "userIdentity": {
+        "principalId": "EXAMPLE"
+      },
+      "requestParameters": {
+        "sourceIPAddress": "127.0.0.1"
+      },
+      "responseElements": {
+        "x-amz-request-id": "EXAMPLE123456789",
+        "x-amz-id-2": "EXAMPLE123/5678abcdefghijklambdaisawesome/mnopqrstuvwxyzABCDEFGH"
+      },
+      "s3": {
+        "s3SchemaVersion": "1.0",
+        "configurationId": "testConfigRule",
+        "bucket": {
+          "name": "example-bucket",
+          "ownerIdentity": {
+            "principalId": "EXAMPLE"
+          },
+          "arn": "arn:aws:s3:::example-bucket"
+        },
+        "object": {
+          "key": "test%2Fkey",
+          "size": 1024,
+          "eTag": "0123456789abcdef0123456789abcdef",
+          "sequencer": "0A1B2C3D4E5F678901"
+        }
+      }
+    }
+  ]
+}"#;
+
+        let event: S3Event = serde_json::from_str(raw_buf)?;
+        Ok(event)
+    }
+
+    /**
+     * Quickly validate that the liquid rendering of things works properly
+     */
+    #[test]
+    fn test_rendering() {
+        let template = liquid::ParserBuilder::with_stdlib()
+            .build()
+            .unwrap()
+            .parse("databases/{{database}}/{{table | remove:'public.'}}/ds={{ds}}/{{filename}}")
+            .unwrap();
+        let mut parameters: HashMap<String, String> = HashMap::new();
+        parameters = add_builtin_parameters(parameters);
+        parameters.insert("database".into(), "oltp".into());
+        parameters.insert("table".into(), "public.a_table".into());
+        parameters.insert("filename".into(), "some.parquet".into());
+        parameters.insert("ds".into(), "2023-09-05".into());
+        let output_key = template.render(&parameters).unwrap();
+        assert_eq!(
+            output_key,
+            "databases/oltp/a_table/ds=2023-09-05/some.parquet"
+        );
+    }
+
+    #[test]
+    fn test_url_decode_key_slash() -> Result<(), anyhow::Error> {
+        assert_eq!(url_decode_key("test%2Fkey")?, "test/key");
+        Ok(())
+    }
+
+    #[test]
+    fn test_url_decode_key_space() -> Result<(), anyhow::Error> {
+        assert_eq!(url_decode_key("test+key+with+spaces")?, "test key with spaces");
+        assert_eq!(url_decode_key("test%20key")?, "test key");
+        Ok(())
+    }
+
+    #[test]
+    fn test_url_decode_key_unicode() -> Result<(), anyhow::Error> {
+        assert_eq!(url_decode_key("path/%E2%98%83.parquet")?, "path/☃.parquet");
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_copy_source_key_space() {
+        assert_eq!(
+            encode_copy_source_key("test key with spaces"),
+            "test%20key%20with%20spaces"
+        );
+    }
+
+    #[test]
+    fn test_encode_copy_source_key_preserves_slashes() {
+        assert_eq!(
+            encode_copy_source_key("path/to/test key.parquet"),
+            "path/to/test%20key.parquet"
+        );
+    }
+
+    #[test]
+    fn test_exclude_regex() {
+        let exclude = Some(
+            Regex::new(r#"^path\/to\/table.*"#).expect("Failed to compile regular expression"),
+        );
+        let keys = vec![
+            "path/to/alpha",
+            "path/to/bravo/foo.parquet",
+            "path/to/table",
+            "path/to/table/foo.parquet",
+        ];
+
+        let filtered: Vec<_> = keys
+            .iter()
+            .filter(|k| !should_exclude(exclude.as_ref(), k))
+            .map(|k| k.clone())
+            .collect();
+        assert_ne!(filtered, keys);
+    }
+
+    #[test]
+    fn test_captured_parameters() {
+        let mut router = Router::new();
+        router.add("/:ignore/livemode/:table/:filename", 1);
+        let parameters = captured_parameters(&router, "2025041518/testmode/sometable/part-00000-6dc656c3-fd08-4377-a846-a36f58f5937b-c000.zstd.parquet");
+        assert_eq!(parameters, None);
+
+        let parameters = captured_parameters(&router, "2025041518/livemode/sometable/part-00000-6dc656c3-fd08-4377-a846-a36f58f5937b-c000.zstd.parquet");
+
+        let mut expected: HashMap<String, String> = HashMap::default();
+        expected.insert("ignore".into(), "2025041518".into());
+        expected.insert("table".into(), "sometable".into());
+        expected.insert(
+            "filename".into(),
+            "part-00000-6dc656c3-fd08-4377-a846-a36f58f5937b-c000.zstd.parquet".into(),
+        );
+
+        assert_eq!(Some((1, expected)), parameters);
+    }
+
+    #[test]
+    fn test_batch_response_serialization() -> Result<(), anyhow::Error> {
+        let response: BatchResponse = vec!["msg-1".to_string(), "msg-2".to_string()].into();
+        let value = serde_json::to_value(&response)?;
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "batchItemFailures": [
+                    {"itemIdentifier": "msg-1"},
+                    {"itemIdentifier": "msg-2"},
+                ]
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_captured_parameters_multi_rule() -> Result<(), anyhow::Error> {
+        let mut router = Router::new();
+        router
+            .add("/alpha/:table/:filename", 0)
+            .map_err(anyhow::Error::msg)?;
+        router
+            .add("/bravo/:table/:filename", 1)
+            .map_err(anyhow::Error::msg)?;
+
+        let (rule_id, parameters) =
+            captured_parameters(&router, "/bravo/sometable/foo.parquet").expect("should match");
+        assert_eq!(rule_id, 1);
+        assert_eq!(parameters.get("table"), Some(&"sometable".to_string()));
+
+        Ok(())
+    }
+}